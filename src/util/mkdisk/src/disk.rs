@@ -0,0 +1,445 @@
+//! In-memory disk images and partition tables.
+//!
+//! A `RamDisk` is just a flat array of equally-sized sectors that we fill in
+//! while building the image and flush to the output file at the very end.
+//! Partitioning is done in-place: `set_pinfo` stamps a legacy MBR entry and
+//! `write_gpt` lays down a GUID Partition Table; `get_partition` and `mount`
+//! hand back a view into the bytes that belong to one partition.
+
+use std::io;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+
+use fs;
+
+/// The sector size every image used historically; still the default.
+pub const DEFAULT_SECTOR_SIZE: usize = 512;
+
+/// The filesystem a partition should be formatted with.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Format {
+    Fat12,
+    Fat16,
+    Fat32,
+    /// Pick FAT12/16/32 from the cluster count at format time.
+    FatAuto,
+}
+
+impl Format {
+    /// The one-byte MBR system id advertised for this format.
+    fn mbr_type(self) -> u8 {
+        match self {
+            Format::Fat12 => 0x01,
+            // FAT16 / FAT32 with LBA addressing
+            Format::Fat16 => 0x0E,
+            // FatAuto should have been resolved before we stamp the table, but
+            // fall back to the FAT32 id so the entry is still meaningful.
+            Format::Fat32 | Format::FatAuto => 0x0C,
+        }
+    }
+}
+
+/// How the partition table at the head of the image is laid out.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum PartitionTable {
+    /// Legacy master boot record: four primary entries in sector 0.
+    Mbr,
+    /// UEFI GUID partition table with a protective MBR.
+    Gpt,
+}
+
+impl PartitionTable {
+    /// Parse the `--table` option value.
+    pub fn parse(s: &str) -> io::Result<PartitionTable> {
+        match s {
+            "mbr" => Ok(PartitionTable::Mbr),
+            "gpt" => Ok(PartitionTable::Gpt),
+            other => Err(err(&format!("unknown partition table `{}`", other))),
+        }
+    }
+}
+
+/// Everything `set_pinfo`/`write_gpt` needs to describe one partition.
+pub struct PartitionInfo {
+    pub format: Format,
+    /// Size of the partition, in sectors.
+    pub size: usize,
+    /// First sector (LBA) of the partition.
+    pub start: usize,
+    pub bootable: bool,
+}
+
+/// A flat, sector-addressed image kept entirely in memory.
+pub struct RamDisk {
+    sector_size: usize,
+    sectors: Vec<Vec<u8>>,
+}
+
+impl RamDisk {
+    /// A fresh, zeroed image of `sectors` sectors of the default size.
+    pub fn new(sectors: usize) -> RamDisk {
+        RamDisk::with_sector_size(sectors, DEFAULT_SECTOR_SIZE)
+    }
+
+    /// A fresh, zeroed image of `sectors` sectors, each `sector_size` bytes.
+    pub fn with_sector_size(sectors: usize, sector_size: usize) -> RamDisk {
+        RamDisk {
+            sector_size: sector_size,
+            sectors: vec![vec![0u8; sector_size]; sectors],
+        }
+    }
+
+    /// Rebuild an image from a flat byte buffer, split into `sector_size`
+    /// sectors. A trailing short sector is zero-padded.
+    pub fn from_raw(data: &[u8], sector_size: usize) -> RamDisk {
+        let count = (data.len() + sector_size - 1) / sector_size;
+        let mut disk = RamDisk::with_sector_size(count, sector_size);
+        for i in 0..count {
+            let start = i * sector_size;
+            let end = ::std::cmp::min(start + sector_size, data.len());
+            disk.write_sector(i, &data[start..end]);
+        }
+        disk
+    }
+
+    /// Number of sectors in the image.
+    pub fn sector_count(&self) -> usize {
+        self.sectors.len()
+    }
+
+    /// Bytes per sector.
+    pub fn sector_size(&self) -> usize {
+        self.sector_size
+    }
+
+    /// Overwrite sector `index` with `data`, copying at most one sector worth.
+    pub fn write_sector(&mut self, index: usize, data: &[u8]) {
+        let sector = &mut self.sectors[index];
+        let n = ::std::cmp::min(sector.len(), data.len());
+        sector[..n].clone_from_slice(&data[..n]);
+    }
+
+    /// Borrow sector `index` read-only.
+    pub fn read_sector(&self, index: usize) -> &[u8] {
+        &self.sectors[index]
+    }
+}
+
+impl Deref for RamDisk {
+    type Target = [Vec<u8>];
+    fn deref(&self) -> &[Vec<u8>] {
+        &self.sectors
+    }
+}
+
+impl DerefMut for RamDisk {
+    fn deref_mut(&mut self) -> &mut [Vec<u8>] {
+        &mut self.sectors
+    }
+}
+
+/// A read/write window onto the sectors owned by one partition.
+pub struct Partition<'a> {
+    disk: &'a mut RamDisk,
+    start: usize,
+    size: usize,
+}
+
+impl<'a> Partition<'a> {
+    /// Number of sectors in the partition.
+    pub fn sector_count(&self) -> usize {
+        self.size
+    }
+
+    /// Bytes per sector, inherited from the backing image.
+    pub fn sector_size(&self) -> usize {
+        self.disk.sector_size()
+    }
+
+    /// Borrow sector `lba` (relative to the partition start) read-only.
+    pub fn read_sector(&self, lba: usize) -> &[u8] {
+        self.disk.read_sector(self.start + lba)
+    }
+
+    /// Overwrite sector `lba` (relative to the partition start).
+    pub fn write_sector(&mut self, lba: usize, data: &[u8]) {
+        self.disk.write_sector(self.start + lba, data)
+    }
+}
+
+/// Construct an `io::Error` the lazy way; the module never leans on the kind.
+fn err(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, msg.to_string())
+}
+
+/// Stamp a legacy MBR partition entry `index` (0..4) into sector 0.
+pub fn set_pinfo(disk: &mut RamDisk, index: usize, pinfo: &PartitionInfo) -> io::Result<()> {
+    if index >= 4 {
+        return Err(err("MBR only has four primary partition entries"));
+    }
+    let mut sector = disk.read_sector(0).to_vec();
+
+    let base = 446 + index * 16;
+    sector[base] = if pinfo.bootable { 0x80 } else { 0x00 };
+    // CHS fields are left zeroed; everyone addresses by LBA now.
+    sector[base + 4] = pinfo.format.mbr_type();
+    LittleEndian::write_u32(&mut sector[base + 8..base + 12], pinfo.start as u32);
+    LittleEndian::write_u32(&mut sector[base + 12..base + 16], pinfo.size as u32);
+
+    // Boot signature.
+    sector[510] = 0x55;
+    sector[511] = 0xAA;
+
+    disk.write_sector(0, &sector);
+    Ok(())
+}
+
+/// Borrow partition `index`, reading its extent from whichever table is present.
+pub fn get_partition(disk: &mut RamDisk, index: usize) -> io::Result<Partition> {
+    let (start, size) = try!(partition_extent(disk, index));
+    Ok(Partition { disk: disk, start: start, size: size })
+}
+
+/// Mount partition `index` as a FAT filesystem, for writing or reading.
+pub fn mount<'a>(disk: &'a mut RamDisk, index: usize) -> io::Result<Box<fs::FileSystem + 'a>> {
+    let part = try!(get_partition(disk, index));
+    Ok(Box::new(try!(fs::fat::FatFs::open(part))))
+}
+
+/// Locate the `(start, size)` of partition `index` from the on-disk table.
+fn partition_extent(disk: &RamDisk, index: usize) -> io::Result<(usize, usize)> {
+    // GPT advertises itself at LBA 1; fall back to the MBR otherwise.
+    if &disk.read_sector(1)[0..8] == b"EFI PART" {
+        let array_lba = LittleEndian::read_u64(&disk.read_sector(1)[72..80]) as usize;
+        let entry_size = LittleEndian::read_u32(&disk.read_sector(1)[84..88]) as usize;
+        let per_sector = disk.sector_size() / entry_size;
+        let sector = disk.read_sector(array_lba + index / per_sector);
+        let off = (index % per_sector) * entry_size;
+        let first = LittleEndian::read_u64(&sector[off + 32..off + 40]) as usize;
+        let last = LittleEndian::read_u64(&sector[off + 40..off + 48]) as usize;
+        if first == 0 && last == 0 {
+            return Err(err("GPT partition entry is empty"));
+        }
+        Ok((first, last - first + 1))
+    } else {
+        let sector = disk.read_sector(0);
+        let base = 446 + index * 16;
+        let start = LittleEndian::read_u32(&sector[base + 8..base + 12]) as usize;
+        let size = LittleEndian::read_u32(&sector[base + 12..base + 16]) as usize;
+        if size == 0 {
+            return Err(err("MBR partition entry is empty"));
+        }
+        Ok((start, size))
+    }
+}
+
+/// Lay down a GUID Partition Table describing `parts`, with a protective MBR
+/// and a mirrored backup header/array at the tail of the image.
+pub fn write_gpt(disk: &mut RamDisk, parts: &[PartitionInfo]) -> io::Result<()> {
+    let ssize = disk.sector_size();
+    let total = disk.sector_count();
+    if total < 67 {
+        // 1 (PMBR) + 1 (header) + 32 (array) on each end leaves no room.
+        return Err(err("image too small for a GPT layout"));
+    }
+    let last_lba = (total - 1) as u64;
+
+    const ENTRY_COUNT: usize = 128;
+    const ENTRY_SIZE: usize = 128;
+    let array_bytes = ENTRY_COUNT * ENTRY_SIZE;
+    let array_sectors = (array_bytes + ssize - 1) / ssize;
+
+    let primary_array_lba: u64 = 2;
+    let backup_header_lba = last_lba;
+    let backup_array_lba = last_lba - array_sectors as u64;
+    let first_usable = primary_array_lba + array_sectors as u64;
+    let last_usable = backup_array_lba - 1;
+
+    // --- protective MBR -------------------------------------------------
+    // The GPT reserves sectors 1..first_usable for its header and entry array,
+    // so any bootloader continuation copied there is discarded.
+    warn!("GPT layout overwrites sectors 1..{}; only the sector-0 boot code is preserved",
+          first_usable);
+    let mut pmbr = vec![0u8; ssize];
+    // Preserve the MBR boot code already laid down (the first 446 bytes), the
+    // way the MBR path does in `set_pinfo`.
+    let existing = disk.read_sector(0).to_vec();
+    pmbr[..446].clone_from_slice(&existing[..446]);
+    {
+        let base = 446;
+        pmbr[base] = 0x00; // not bootable
+        pmbr[base + 4] = 0xEE; // GPT protective
+        LittleEndian::write_u32(&mut pmbr[base + 8..base + 12], 1);
+        let span = if last_lba > 0xFFFF_FFFF { 0xFFFF_FFFF } else { last_lba as u32 };
+        LittleEndian::write_u32(&mut pmbr[base + 12..base + 16], span);
+        pmbr[510] = 0x55;
+        pmbr[511] = 0xAA;
+    }
+    disk.write_sector(0, &pmbr);
+
+    // --- partition entry array ------------------------------------------
+    let mut array = vec![0u8; array_bytes];
+    for (i, part) in parts.iter().enumerate() {
+        let off = i * ENTRY_SIZE;
+        // Microsoft basic data partition type GUID.
+        array[off..off + 16].clone_from_slice(&guid_microsoft_basic_data());
+        array[off + 16..off + 32].clone_from_slice(&random_guid());
+        // Keep the partition inside the usable range: the caller sizes it
+        // against the whole disk, but the backup header/array occupy the final
+        // sectors, so clamp the last LBA to `last_usable`.
+        let first = ::std::cmp::max(part.start as u64, first_usable);
+        let last = ::std::cmp::min((part.start + part.size - 1) as u64, last_usable);
+        LittleEndian::write_u64(&mut array[off + 32..off + 40], first);
+        LittleEndian::write_u64(&mut array[off + 40..off + 48], last);
+        // attributes left zero
+        let name = format!("Partition {}", i + 1);
+        write_utf16le(&mut array[off + 56..off + 128], &name);
+    }
+    let array_crc = crc32(&array);
+
+    // --- primary + backup headers ---------------------------------------
+    let disk_guid = random_guid();
+    let primary = gpt_header(GptHeader {
+        current_lba: 1,
+        backup_lba: backup_header_lba,
+        first_usable: first_usable,
+        last_usable: last_usable,
+        disk_guid: disk_guid,
+        array_lba: primary_array_lba,
+        array_entries: ENTRY_COUNT as u32,
+        array_entry_size: ENTRY_SIZE as u32,
+        array_crc: array_crc,
+    }, ssize);
+    let backup = gpt_header(GptHeader {
+        current_lba: backup_header_lba,
+        backup_lba: 1,
+        first_usable: first_usable,
+        last_usable: last_usable,
+        disk_guid: disk_guid,
+        array_lba: backup_array_lba,
+        array_entries: ENTRY_COUNT as u32,
+        array_entry_size: ENTRY_SIZE as u32,
+        array_crc: array_crc,
+    }, ssize);
+
+    disk.write_sector(1, &primary);
+    write_region(disk, primary_array_lba as usize, &array, ssize);
+    write_region(disk, backup_array_lba as usize, &array, ssize);
+    disk.write_sector(backup_header_lba as usize, &backup);
+
+    Ok(())
+}
+
+/// Scratch description of a GPT header, before serialization.
+struct GptHeader {
+    current_lba: u64,
+    backup_lba: u64,
+    first_usable: u64,
+    last_usable: u64,
+    disk_guid: [u8; 16],
+    array_lba: u64,
+    array_entries: u32,
+    array_entry_size: u32,
+    array_crc: u32,
+}
+
+/// Serialize a GPT header into a fresh sector, with its CRC32 filled in.
+fn gpt_header(h: GptHeader, ssize: usize) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(92);
+    buf.extend_from_slice(b"EFI PART");
+    buf.write_u32::<LittleEndian>(0x0001_0000).unwrap(); // revision 1.0
+    buf.write_u32::<LittleEndian>(92).unwrap(); // header size
+    buf.write_u32::<LittleEndian>(0).unwrap(); // header CRC placeholder
+    buf.write_u32::<LittleEndian>(0).unwrap(); // reserved
+    buf.write_u64::<LittleEndian>(h.current_lba).unwrap();
+    buf.write_u64::<LittleEndian>(h.backup_lba).unwrap();
+    buf.write_u64::<LittleEndian>(h.first_usable).unwrap();
+    buf.write_u64::<LittleEndian>(h.last_usable).unwrap();
+    buf.extend_from_slice(&h.disk_guid);
+    buf.write_u64::<LittleEndian>(h.array_lba).unwrap();
+    buf.write_u32::<LittleEndian>(h.array_entries).unwrap();
+    buf.write_u32::<LittleEndian>(h.array_entry_size).unwrap();
+    buf.write_u32::<LittleEndian>(h.array_crc).unwrap();
+
+    // CRC is computed over the header with its own CRC field zeroed.
+    let crc = crc32(&buf);
+    LittleEndian::write_u32(&mut buf[16..20], crc);
+
+    let mut sector = vec![0u8; ssize];
+    sector[..buf.len()].clone_from_slice(&buf);
+    sector
+}
+
+/// Write a byte blob across consecutive sectors starting at `lba`.
+fn write_region(disk: &mut RamDisk, lba: usize, data: &[u8], ssize: usize) {
+    let mut off = 0;
+    let mut sector = lba;
+    while off < data.len() {
+        let end = ::std::cmp::min(off + ssize, data.len());
+        disk.write_sector(sector, &data[off..end]);
+        off = end;
+        sector += 1;
+    }
+}
+
+/// Encode `s` as a NUL-padded UTF-16LE name filling `out`.
+fn write_utf16le(out: &mut [u8], s: &str) {
+    let mut i = 0;
+    for unit in s.encode_utf16() {
+        if i + 2 > out.len() {
+            break;
+        }
+        LittleEndian::write_u16(&mut out[i..i + 2], unit);
+        i += 2;
+    }
+}
+
+/// CRC32 with the IEEE polynomial, matching the `crc32fast` crate.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc: u32 = 0xFFFF_FFFF;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+/// The well-known "Microsoft basic data" partition type GUID, mixed-endian.
+fn guid_microsoft_basic_data() -> [u8; 16] {
+    [
+        0xA2, 0xA0, 0xD0, 0xEB, 0xE5, 0xB9, 0x33, 0x44,
+        0x87, 0xC0, 0x68, 0xB6, 0xB7, 0x26, 0x99, 0xC7,
+    ]
+}
+
+/// A pseudo-random GUID seeded from the clock and a per-call counter; we only
+/// need uniqueness within one image, not cryptographic quality.
+fn random_guid() -> [u8; 16] {
+    use std::time::{SystemTime, UNIX_EPOCH};
+    static COUNTER: AtomicUsize = ATOMIC_USIZE_INIT;
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|d| (d.as_secs() ^ (d.subsec_nanos() as u64)))
+        .unwrap_or(0x9E37_79B9_7F4A_7C15);
+    let mut state = now
+        ^ ((COUNTER.fetch_add(1, Ordering::SeqCst) as u64).wrapping_mul(0x2545_F491_4F6C_DD1D))
+        | 1;
+
+    let mut guid = [0u8; 16];
+    for b in guid.iter_mut() {
+        // xorshift64
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        *b = (state >> 24) as u8;
+    }
+    // Set the version (4) and variant bits so it is a well-formed random GUID.
+    guid[7] = (guid[7] & 0x0F) | 0x40;
+    guid[8] = (guid[8] & 0x3F) | 0x80;
+    guid
+}
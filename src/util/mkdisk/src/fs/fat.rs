@@ -0,0 +1,1072 @@
+//! The FAT family of filesystems (FAT12 / FAT16 / FAT32).
+//!
+//! `format` lays down a fresh volume inside a partition; `FatFs` then drives
+//! the write path used while copying the host tree in. The three FAT widths
+//! differ only in how a cluster number is packed into the table and in the
+//! shape of the root directory, so that variation is captured by `FatWidth`
+//! and `Layout` and threaded through the rest of the code.
+
+use std::ascii::AsciiExt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use disk::{Format, Partition};
+use fs::{DirEntry, EntryKind, FileSystem, Timestamps};
+
+/// A date/time packed into the two 16-bit fields FAT directory entries use.
+#[derive(Copy, Clone)]
+struct DosDateTime {
+    date: u16,
+    time: u16,
+}
+
+impl DosDateTime {
+    /// The FAT epoch, 1980-01-01 00:00:00, used when a host time is missing.
+    fn epoch() -> DosDateTime {
+        DosDateTime { date: (1 << 5) | 1, time: 0 }
+    }
+
+    /// Convert a host `SystemTime` to its FAT representation (2-second
+    /// resolution, years clamped to the 1980..2107 range FAT can express).
+    fn from_system_time(t: SystemTime) -> DosDateTime {
+        let secs = match t.duration_since(UNIX_EPOCH) {
+            Ok(d) => d.as_secs() as i64,
+            Err(_) => return DosDateTime::epoch(),
+        };
+        let days = if secs >= 0 { secs / 86_400 } else { (secs - 86_399) / 86_400 };
+        let tod = secs - days * 86_400;
+        let (year, month, day) = civil_from_days(days);
+        if year < 1980 {
+            return DosDateTime::epoch();
+        }
+        let year = if year > 2107 { 2107 } else { year };
+
+        let date = (((year - 1980) as u16) << 9) | ((month as u16) << 5) | (day as u16);
+        let time = (((tod / 3600) as u16) << 11)
+                 | ((((tod % 3600) / 60) as u16) << 5)
+                 | (((tod % 60) / 2) as u16);
+        DosDateTime { date: date, time: time }
+    }
+
+    /// Unpack the stored fields back into a host `SystemTime`. The FAT epoch
+    /// maps to 1980-01-01, so the result is never before the Unix epoch.
+    fn to_system_time(self) -> SystemTime {
+        let year = 1980 + (self.date >> 9) as i64;
+        let month = ((self.date >> 5) & 0x0F) as u32;
+        let day = (self.date & 0x1F) as u32;
+        let hour = (self.time >> 11) as i64;
+        let minute = ((self.time >> 5) & 0x3F) as i64;
+        let second = ((self.time & 0x1F) * 2) as i64;
+
+        let days = days_from_civil(year, month.max(1), day.max(1));
+        let secs = days * 86_400 + hour * 3600 + minute * 60 + second;
+        if secs >= 0 {
+            UNIX_EPOCH + Duration::from_secs(secs as u64)
+        } else {
+            UNIX_EPOCH - Duration::from_secs((-secs) as u64)
+        }
+    }
+}
+
+/// Convert a count of days since the Unix epoch to a `(year, month, day)`
+/// civil date (Howard Hinnant's algorithm).
+fn civil_from_days(z: i64) -> (i64, u32, u32) {
+    let z = z + 719_468;
+    let era = if z >= 0 { z } else { z - 146_096 } / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let y = yoe + era * 400;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let m = (if mp < 10 { mp + 3 } else { mp - 9 }) as u32;
+    (if m <= 2 { y + 1 } else { y }, m, d)
+}
+
+/// Inverse of `civil_from_days`: the day count since the Unix epoch for a civil
+/// `(year, month, day)` date (Howard Hinnant's algorithm).
+fn days_from_civil(y: i64, m: u32, d: u32) -> i64 {
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = (if y >= 0 { y } else { y - 399 }) / 400;
+    let yoe = y - era * 400;
+    let mp = (if m > 2 { m - 3 } else { m + 9 }) as i64;
+    let doy = (153 * mp + 2) / 5 + d as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+/// Microsoft's cluster-count thresholds separating the three FAT widths.
+const FAT12_MAX_CLUSTERS: u32 = 4085;
+const FAT16_MAX_CLUSTERS: u32 = 65525;
+
+/// The width of a single FAT entry.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum FatWidth {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+impl FatWidth {
+    fn from_format(fmt: Format) -> FatWidth {
+        match fmt {
+            Format::Fat12 => FatWidth::Fat12,
+            Format::Fat16 => FatWidth::Fat16,
+            Format::Fat32 => FatWidth::Fat32,
+            Format::FatAuto => unreachable!("FatAuto must be resolved before use"),
+        }
+    }
+
+    /// End-of-chain marker for this width.
+    fn eoc(self) -> u32 {
+        match self {
+            FatWidth::Fat12 => 0x0FFF,
+            FatWidth::Fat16 => 0xFFFF,
+            FatWidth::Fat32 => 0x0FFF_FFFF,
+        }
+    }
+}
+
+/// Read the `cluster`'th entry out of a FAT, honouring its width. FAT12 entries
+/// are 1.5 bytes, split across byte boundaries depending on parity.
+pub fn get_fat_entry(fat: &[u8], cluster: u32, width: FatWidth) -> u32 {
+    match width {
+        FatWidth::Fat12 => {
+            let off = (cluster + cluster / 2) as usize;
+            let word = LittleEndian::read_u16(&fat[off..off + 2]);
+            if cluster & 1 == 0 {
+                (word & 0x0FFF) as u32
+            } else {
+                (word >> 4) as u32
+            }
+        }
+        FatWidth::Fat16 => {
+            let off = cluster as usize * 2;
+            LittleEndian::read_u16(&fat[off..off + 2]) as u32
+        }
+        FatWidth::Fat32 => {
+            let off = cluster as usize * 4;
+            LittleEndian::read_u32(&fat[off..off + 4]) & 0x0FFF_FFFF
+        }
+    }
+}
+
+/// Write the `cluster`'th entry into a FAT, honouring its width. For FAT12 the
+/// neighbouring nibble sharing the 16-bit window is preserved.
+pub fn set_fat_entry(fat: &mut [u8], cluster: u32, value: u32, width: FatWidth) {
+    match width {
+        FatWidth::Fat12 => {
+            let off = (cluster + cluster / 2) as usize;
+            let mut word = LittleEndian::read_u16(&fat[off..off + 2]);
+            if cluster & 1 == 0 {
+                word = (word & 0xF000) | (value as u16 & 0x0FFF);
+            } else {
+                word = (word & 0x000F) | ((value as u16 & 0x0FFF) << 4);
+            }
+            LittleEndian::write_u16(&mut fat[off..off + 2], word);
+        }
+        FatWidth::Fat16 => {
+            let off = cluster as usize * 2;
+            LittleEndian::write_u16(&mut fat[off..off + 2], value as u16);
+        }
+        FatWidth::Fat32 => {
+            let off = cluster as usize * 4;
+            let top = LittleEndian::read_u32(&fat[off..off + 4]) & 0xF000_0000;
+            LittleEndian::write_u32(&mut fat[off..off + 4], top | (value & 0x0FFF_FFFF));
+        }
+    }
+}
+
+/// The geometry of a FAT volume: everything the BPB and the on-disk regions
+/// are computed from.
+#[derive(Copy, Clone, Debug)]
+pub struct Layout {
+    pub width: FatWidth,
+    pub bytes_per_sector: usize,
+    pub sectors_per_cluster: usize,
+    pub reserved_sectors: usize,
+    pub num_fats: usize,
+    pub root_entries: usize,
+    pub total_sectors: usize,
+    pub fat_sectors: usize,
+    pub root_sectors: usize,
+}
+
+impl Layout {
+    /// First sector (partition-relative) of the data region.
+    fn first_data_sector(&self) -> usize {
+        self.reserved_sectors + self.num_fats * self.fat_sectors + self.root_sectors
+    }
+}
+
+/// A sensible cluster size for a volume of `total_sectors` sectors. Small
+/// images (the 64KiB floor this tool allows) stay at one sector per cluster.
+fn sectors_per_cluster(total_sectors: usize) -> usize {
+    match total_sectors {
+        0...8_192 => 1,           // up to 4MiB
+        8_193...65_536 => 8,      // up to 32MiB
+        65_537...524_288 => 16,   // up to 256MiB
+        _ => 32,
+    }
+}
+
+/// Estimate the number of data clusters for a volume laid out with the given
+/// parameters. The FAT overhead per cluster is folded in directly rather than
+/// via the spec's iterative `FATSz` approximation.
+fn cluster_count(total_sectors: usize, bps: usize, spc: usize,
+                 reserved: usize, num_fats: usize, root_entries: usize,
+                 bits: usize) -> u32 {
+    let root_sectors = (root_entries * 32 + bps - 1) / bps;
+    let avail = total_sectors.saturating_sub(reserved + root_sectors);
+    // Each cluster consumes `spc * bps` data bytes plus `bits/8` bytes in each
+    // FAT; solve `avail * bps = clusters * (spc*bps + num_fats*bits/8)`.
+    let numerator = (avail * bps * 8) as u64;
+    let denominator = (spc * bps * 8 + num_fats * bits) as u64;
+    (numerator / denominator) as u32
+}
+
+/// Resolve `requested` to a concrete FAT format, applying the Microsoft
+/// cluster-count rule when `FatAuto` is asked for.
+pub fn resolve_format(total_sectors: usize, bps: usize, requested: Format) -> Format {
+    if requested != Format::FatAuto {
+        return requested;
+    }
+    let spc = sectors_per_cluster(total_sectors);
+    // FAT12 and FAT16 share reserved=1 / 512-entry root geometry, so a 16-bit
+    // trial gives the cluster count used to classify between all three.
+    let clusters = cluster_count(total_sectors, bps, spc, 1, 2, 512, 16);
+    if clusters < FAT12_MAX_CLUSTERS {
+        Format::Fat12
+    } else if clusters < FAT16_MAX_CLUSTERS {
+        Format::Fat16
+    } else {
+        Format::Fat32
+    }
+}
+
+/// Compute the full geometry for a (resolved) FAT volume.
+pub fn layout(total_sectors: usize, bps: usize, fmt: Format) -> Layout {
+    let fmt = resolve_format(total_sectors, bps, fmt);
+    let width = FatWidth::from_format(fmt);
+    let spc = sectors_per_cluster(total_sectors);
+
+    let (reserved, root_entries, bits) = match width {
+        FatWidth::Fat12 => (1, 512, 12),
+        FatWidth::Fat16 => (1, 512, 16),
+        FatWidth::Fat32 => (32, 0, 32),
+    };
+    let num_fats = 2;
+    let root_sectors = (root_entries * 32 + bps - 1) / bps;
+
+    let clusters = cluster_count(total_sectors, bps, spc, reserved, num_fats, root_entries, bits);
+    // Size each FAT to hold `clusters + 2` entries (clusters 0 and 1 reserved).
+    let fat_bytes = ((clusters as usize + 2) * bits + 7) / 8;
+    let fat_sectors = (fat_bytes + bps - 1) / bps;
+
+    Layout {
+        width: width,
+        bytes_per_sector: bps,
+        sectors_per_cluster: spc,
+        reserved_sectors: reserved,
+        num_fats: num_fats,
+        root_entries: root_entries,
+        total_sectors: total_sectors,
+        fat_sectors: fat_sectors,
+        root_sectors: root_sectors,
+    }
+}
+
+/// Format `part` as a fresh FAT volume of the requested (or auto-selected) type.
+pub fn format(part: &mut Partition, fmt: Format) -> io::Result<()> {
+    let bps = part.sector_size();
+    let total = part.sector_count();
+    let lay = layout(total, bps, fmt);
+
+    // --- boot sector / BPB ---------------------------------------------
+    let mut boot = vec![0u8; bps];
+    boot[0] = 0xEB;
+    boot[1] = 0x3C;
+    boot[2] = 0x90;
+    boot[3..11].clone_from_slice(b"MKDISK  ");
+    LittleEndian::write_u16(&mut boot[11..13], bps as u16);
+    boot[13] = lay.sectors_per_cluster as u8;
+    LittleEndian::write_u16(&mut boot[14..16], lay.reserved_sectors as u16);
+    boot[16] = lay.num_fats as u8;
+    LittleEndian::write_u16(&mut boot[17..19], lay.root_entries as u16);
+    // Exactly one of TotSec16/TotSec32 must be non-zero; small volumes use the
+    // 16-bit field and leave the 32-bit one zeroed, larger ones the reverse.
+    if total < 0x1_0000 {
+        LittleEndian::write_u16(&mut boot[19..21], total as u16);
+    }
+    boot[21] = 0xF8; // fixed media
+    LittleEndian::write_u16(&mut boot[24..26], 63); // sectors per track
+    LittleEndian::write_u16(&mut boot[26..28], 255); // heads
+    if total >= 0x1_0000 {
+        LittleEndian::write_u32(&mut boot[32..36], total as u32);
+    }
+
+    match lay.width {
+        FatWidth::Fat12 | FatWidth::Fat16 => {
+            LittleEndian::write_u16(&mut boot[22..24], lay.fat_sectors as u16);
+            boot[36] = 0x80; // drive number
+            boot[38] = 0x29; // extended boot signature
+            LittleEndian::write_u32(&mut boot[39..43], 0x1234_5678);
+            boot[43..54].clone_from_slice(b"NO NAME    ");
+            let fs_type: &[u8] = if lay.width == FatWidth::Fat12 { b"FAT12   " } else { b"FAT16   " };
+            boot[54..62].clone_from_slice(fs_type);
+        }
+        FatWidth::Fat32 => {
+            LittleEndian::write_u32(&mut boot[36..40], lay.fat_sectors as u32);
+            LittleEndian::write_u32(&mut boot[44..48], 2); // root cluster
+            LittleEndian::write_u16(&mut boot[48..50], 1); // FSInfo sector
+            LittleEndian::write_u16(&mut boot[50..52], 6); // backup boot sector
+            boot[64] = 0x80;
+            boot[66] = 0x29;
+            LittleEndian::write_u32(&mut boot[67..71], 0x1234_5678);
+            boot[71..82].clone_from_slice(b"NO NAME    ");
+            boot[82..90].clone_from_slice(b"FAT32   ");
+        }
+    }
+    boot[510] = 0x55;
+    boot[511] = 0xAA;
+    part.write_sector(0, &boot);
+
+    // --- FATs ----------------------------------------------------------
+    // Build one FAT in memory, seed the reserved entries, then mirror it.
+    let mut fat = vec![0u8; lay.fat_sectors * bps];
+    set_fat_entry(&mut fat, 0, 0x0FFF_FF00 | 0xF8, lay.width);
+    set_fat_entry(&mut fat, 1, lay.width.eoc(), lay.width);
+    if lay.width == FatWidth::Fat32 {
+        // Cluster 2 is the (initially empty) root directory.
+        set_fat_entry(&mut fat, 2, lay.width.eoc(), lay.width);
+    }
+    for copy in 0..lay.num_fats {
+        let base = lay.reserved_sectors + copy * lay.fat_sectors;
+        for s in 0..lay.fat_sectors {
+            part.write_sector(base + s, &fat[s * bps..(s + 1) * bps]);
+        }
+    }
+
+    // --- root directory ------------------------------------------------
+    let blank = vec![0u8; bps];
+    match lay.width {
+        FatWidth::Fat12 | FatWidth::Fat16 => {
+            let base = lay.reserved_sectors + lay.num_fats * lay.fat_sectors;
+            for s in 0..lay.root_sectors {
+                part.write_sector(base + s, &blank);
+            }
+        }
+        FatWidth::Fat32 => {
+            let base = lay.first_data_sector();
+            for s in 0..lay.sectors_per_cluster {
+                part.write_sector(base + s, &blank);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Where a directory lives on the volume.
+#[derive(Copy, Clone)]
+enum DirLoc {
+    /// The fixed-size root directory region of a FAT12/16 volume.
+    FixedRoot,
+    /// A directory stored as a cluster chain (all dirs on FAT32, subdirs
+    /// everywhere).
+    Chain(u32),
+}
+
+/// A mounted FAT volume, used to populate the image.
+pub struct FatFs<'a> {
+    part: Partition<'a>,
+    lay: Layout,
+    /// In-memory working copy of the FAT, flushed back after each change.
+    fat: Vec<u8>,
+    root_cluster: u32,
+}
+
+impl<'a> FatFs<'a> {
+    /// Read the BPB of an already-formatted partition and prepare for writes.
+    pub fn open(part: Partition<'a>) -> io::Result<FatFs<'a>> {
+        let bps = part.sector_size();
+        let boot = part.read_sector(0).to_vec();
+
+        let reserved = LittleEndian::read_u16(&boot[14..16]) as usize;
+        let num_fats = boot[16] as usize;
+        let root_entries = LittleEndian::read_u16(&boot[17..19]) as usize;
+        let spc = boot[13] as usize;
+        let total16 = LittleEndian::read_u16(&boot[19..21]) as usize;
+        let total32 = LittleEndian::read_u32(&boot[32..36]) as usize;
+        let total = if total16 != 0 { total16 } else { total32 };
+
+        let fat16 = LittleEndian::read_u16(&boot[22..24]) as usize;
+        let fat_sectors = if fat16 != 0 {
+            fat16
+        } else {
+            LittleEndian::read_u32(&boot[36..40]) as usize
+        };
+        let root_sectors = (root_entries * 32 + bps - 1) / bps;
+
+        // Trust the FS-type label the formatter stamped into the BPB; that is
+        // the authoritative width, and recomputing a cluster count here risks
+        // disagreeing with `layout` near the 4085/65525 boundaries. FAT32 keeps
+        // its label at offset 82, FAT12/16 at offset 54.
+        let width = if &boot[82..87] == b"FAT32" {
+            FatWidth::Fat32
+        } else if &boot[54..59] == b"FAT16" {
+            FatWidth::Fat16
+        } else if &boot[54..59] == b"FAT12" {
+            FatWidth::Fat12
+        } else {
+            // Fall back to the shared cluster-count classifier for volumes that
+            // carry no recognisable label.
+            let clusters = cluster_count(total, bps, spc, reserved, num_fats, root_entries, 16);
+            if clusters < FAT12_MAX_CLUSTERS {
+                FatWidth::Fat12
+            } else if clusters < FAT16_MAX_CLUSTERS {
+                FatWidth::Fat16
+            } else {
+                FatWidth::Fat32
+            }
+        };
+        let root_cluster = if width == FatWidth::Fat32 {
+            LittleEndian::read_u32(&boot[44..48])
+        } else {
+            0
+        };
+
+        let lay = Layout {
+            width: width,
+            bytes_per_sector: bps,
+            sectors_per_cluster: spc,
+            reserved_sectors: reserved,
+            num_fats: num_fats,
+            root_entries: root_entries,
+            total_sectors: total,
+            fat_sectors: fat_sectors,
+            root_sectors: root_sectors,
+        };
+
+        // Load the first FAT copy into memory.
+        let mut fat = Vec::with_capacity(fat_sectors * bps);
+        for s in 0..fat_sectors {
+            fat.extend_from_slice(part.read_sector(reserved + s));
+        }
+
+        Ok(FatFs { part: part, lay: lay, fat: fat, root_cluster: root_cluster })
+    }
+
+    fn cluster_size(&self) -> usize {
+        self.lay.sectors_per_cluster * self.lay.bytes_per_sector
+    }
+
+    /// First partition-relative sector of a data cluster.
+    fn cluster_sector(&self, cluster: u32) -> usize {
+        self.lay.first_data_sector() + (cluster as usize - 2) * self.lay.sectors_per_cluster
+    }
+
+    fn get_fat(&self, cluster: u32) -> u32 {
+        get_fat_entry(&self.fat, cluster, self.lay.width)
+    }
+
+    /// Update a FAT entry in memory and mirror the affected sector to every
+    /// FAT copy on the partition.
+    fn set_fat(&mut self, cluster: u32, value: u32) {
+        set_fat_entry(&mut self.fat, cluster, value, self.lay.width);
+        let bps = self.lay.bytes_per_sector;
+        let byte = match self.lay.width {
+            FatWidth::Fat12 => (cluster + cluster / 2) as usize,
+            FatWidth::Fat16 => cluster as usize * 2,
+            FatWidth::Fat32 => cluster as usize * 4,
+        };
+        // A FAT12 entry can straddle two sectors; flush both to be safe.
+        let first = byte / bps;
+        let last = (byte + 1) / bps;
+        for s in first..last + 1 {
+            if s >= self.lay.fat_sectors {
+                break;
+            }
+            let src = self.fat[s * bps..(s + 1) * bps].to_vec();
+            for copy in 0..self.lay.num_fats {
+                self.part.write_sector(self.lay.reserved_sectors + copy * self.lay.fat_sectors + s, &src);
+            }
+        }
+    }
+
+    /// Allocate a single free cluster, marking it as an end-of-chain, and
+    /// zero its sectors. Returns the cluster number.
+    fn alloc_cluster(&mut self) -> io::Result<u32> {
+        let total_clusters = 2 + (self.lay.total_sectors.saturating_sub(self.lay.first_data_sector())
+                                  / self.lay.sectors_per_cluster) as u32;
+        for cluster in 2..total_clusters {
+            if self.get_fat(cluster) == 0 {
+                self.set_fat(cluster, self.lay.width.eoc());
+                let base = self.cluster_sector(cluster);
+                let blank = vec![0u8; self.lay.bytes_per_sector];
+                for s in 0..self.lay.sectors_per_cluster {
+                    self.part.write_sector(base + s, &blank);
+                }
+                return Ok(cluster);
+            }
+        }
+        Err(io::Error::new(io::ErrorKind::Other, "no free clusters left"))
+    }
+
+    /// The partition-relative sectors making up `loc`, in order.
+    fn dir_sectors(&self, loc: DirLoc) -> Vec<usize> {
+        match loc {
+            DirLoc::FixedRoot => {
+                let base = self.lay.reserved_sectors + self.lay.num_fats * self.lay.fat_sectors;
+                (0..self.lay.root_sectors).map(|s| base + s).collect()
+            }
+            DirLoc::Chain(start) => {
+                let mut out = Vec::new();
+                let mut cluster = start;
+                while cluster >= 2 && cluster < self.lay.width.eoc() {
+                    let base = self.cluster_sector(cluster);
+                    for s in 0..self.lay.sectors_per_cluster {
+                        out.push(base + s);
+                    }
+                    cluster = self.get_fat(cluster);
+                }
+                out
+            }
+        }
+    }
+
+    /// Append a run of consecutive 32-byte directory entries to `loc`, growing
+    /// a chained directory by a cluster if there is no contiguous gap large
+    /// enough. A long name and its short entry must stay adjacent, so they are
+    /// placed as one run.
+    fn push_entries(&mut self, loc: DirLoc, entries: &[[u8; 32]]) -> io::Result<()> {
+        let bps = self.lay.bytes_per_sector;
+        let per_sector = bps / 32;
+        loop {
+            // Flatten the directory into a slot index space to find a run.
+            let sectors = self.dir_sectors(loc);
+            let mut run_start = None;
+            for (i, &sector) in sectors.iter().enumerate() {
+                let buf = self.part.read_sector(sector);
+                for slot in 0..per_sector {
+                    let first = buf[slot * 32];
+                    let idx = i * per_sector + slot;
+                    if first == 0x00 || first == 0xE5 {
+                        let begin = *run_start.get_or_insert(idx);
+                        if idx + 1 - begin >= entries.len() {
+                            self.place_entries(&sectors, begin, entries);
+                            return Ok(());
+                        }
+                    } else {
+                        run_start = None;
+                    }
+                }
+            }
+            // No run long enough: grow the directory or give up on a full root.
+            match loc {
+                DirLoc::FixedRoot => {
+                    return Err(io::Error::new(io::ErrorKind::Other, "root directory is full"));
+                }
+                DirLoc::Chain(start) => {
+                    let new = try!(self.alloc_cluster());
+                    let mut tail = start;
+                    while self.get_fat(tail) < self.lay.width.eoc() && self.get_fat(tail) >= 2 {
+                        tail = self.get_fat(tail);
+                    }
+                    self.set_fat(tail, new);
+                }
+            }
+        }
+    }
+
+    /// Copy `entries` into the directory `sectors` starting at flat slot index
+    /// `begin`, writing back each touched sector.
+    fn place_entries(&mut self, sectors: &[usize], begin: usize, entries: &[[u8; 32]]) {
+        let per_sector = self.lay.bytes_per_sector / 32;
+        for (n, entry) in entries.iter().enumerate() {
+            let idx = begin + n;
+            let sector = sectors[idx / per_sector];
+            let off = (idx % per_sector) * 32;
+            let mut buf = self.part.read_sector(sector).to_vec();
+            buf[off..off + 32].clone_from_slice(entry);
+            self.part.write_sector(sector, &buf);
+        }
+    }
+
+    /// Is `name` already taken (short name, case-insensitive) in `loc`?
+    fn name_exists(&self, loc: DirLoc, name: &[u8; 11]) -> bool {
+        let bps = self.lay.bytes_per_sector;
+        for sector in self.dir_sectors(loc) {
+            let buf = self.part.read_sector(sector);
+            let mut off = 0;
+            while off + 32 <= bps {
+                let first = buf[off];
+                if first == 0x00 {
+                    return false;
+                }
+                if first != 0xE5 && buf[off + 11] & 0x0F != 0x0F && &buf[off..off + 11] == &name[..] {
+                    return true;
+                }
+                off += 32;
+            }
+        }
+        false
+    }
+
+    /// Choose a short name for `leaf` in `loc`, appending a `~N` numeric tail
+    /// when the straightforward 8.3 form collides with an existing entry.
+    fn unique_short_name(&self, loc: DirLoc, leaf: &str) -> [u8; 11] {
+        let base = short_name(leaf);
+        if !self.name_exists(loc, &base) {
+            return base;
+        }
+        for n in 1..1000 {
+            let candidate = short_name_numbered(leaf, n);
+            if !self.name_exists(loc, &candidate) {
+                return candidate;
+            }
+        }
+        base
+    }
+
+    /// The directory holding the final component of `path`; intermediate
+    /// components must already exist. Descends with the same long-name-aware
+    /// matching as the read path, so directories stored under a mangled `~N`
+    /// alias are still resolved by their real name.
+    fn parent_dir(&self, path: &PathBuf) -> io::Result<DirLoc> {
+        match path.parent() {
+            Some(parent) => self.resolve_dir(parent),
+            None => Ok(if self.lay.width == FatWidth::Fat32 {
+                DirLoc::Chain(self.root_cluster)
+            } else {
+                DirLoc::FixedRoot
+            }),
+        }
+    }
+
+    /// Build a fresh directory entry for `name` with the given attribute,
+    /// first cluster, size and timestamps.
+    fn dir_entry(name: &[u8; 11], attr: u8, cluster: u32, size: u32,
+                 times: &Timestamps) -> [u8; 32] {
+        let created = times.created.map(DosDateTime::from_system_time)
+                           .unwrap_or_else(DosDateTime::epoch);
+        let modified = times.modified.map(DosDateTime::from_system_time)
+                            .unwrap_or(created);
+
+        let mut e = [0u8; 32];
+        e[0..11].clone_from_slice(name);
+        e[11] = attr;
+        LittleEndian::write_u16(&mut e[14..16], created.time);
+        LittleEndian::write_u16(&mut e[16..18], created.date);
+        LittleEndian::write_u16(&mut e[18..20], modified.date); // last access date
+        LittleEndian::write_u16(&mut e[20..22], (cluster >> 16) as u16);
+        LittleEndian::write_u16(&mut e[22..24], modified.time);
+        LittleEndian::write_u16(&mut e[24..26], modified.date);
+        LittleEndian::write_u16(&mut e[26..28], cluster as u16);
+        LittleEndian::write_u32(&mut e[28..32], size);
+        e
+    }
+
+    /// Walk the directory at `loc`, reassembling any long names, and return one
+    /// `RawEntry` per live child. Volume labels and the `.`/`..` links are
+    /// dropped.
+    fn list_raw(&self, loc: DirLoc) -> Vec<RawEntry> {
+        let mut out = Vec::new();
+        let mut lfn: Vec<(u8, String)> = Vec::new();
+        for sector in self.dir_sectors(loc) {
+            let buf = self.part.read_sector(sector);
+            let mut off = 0;
+            while off + 32 <= buf.len() {
+                let e = &buf[off..off + 32];
+                off += 32;
+                match e[0] {
+                    0x00 => return out, // no entries follow
+                    0xE5 => { lfn.clear(); continue; } // deleted slot
+                    _ => {}
+                }
+                let attr = e[11];
+                if attr & 0x0F == 0x0F {
+                    lfn.push((e[0] & 0x3F, lfn_fragment(e)));
+                    continue;
+                }
+                if attr & 0x08 != 0 {
+                    lfn.clear(); // volume label, not a file
+                    continue;
+                }
+                let short = short_name_to_string(&e[0..11]);
+                if short == "." || short == ".." {
+                    lfn.clear();
+                    continue;
+                }
+                let name = if lfn.is_empty() {
+                    short
+                } else {
+                    lfn.sort_by(|a, b| a.0.cmp(&b.0));
+                    lfn.iter().map(|p| p.1.as_str()).collect()
+                };
+                lfn.clear();
+
+                let hi = LittleEndian::read_u16(&e[20..22]) as u32;
+                let lo = LittleEndian::read_u16(&e[26..28]) as u32;
+                out.push(RawEntry {
+                    name: name,
+                    is_dir: attr & 0x10 != 0,
+                    size: LittleEndian::read_u32(&e[28..32]),
+                    cluster: (hi << 16) | lo,
+                    created: DosDateTime {
+                        date: LittleEndian::read_u16(&e[16..18]),
+                        time: LittleEndian::read_u16(&e[14..16]),
+                    },
+                    modified: DosDateTime {
+                        date: LittleEndian::read_u16(&e[24..26]),
+                        time: LittleEndian::read_u16(&e[22..24]),
+                    },
+                });
+            }
+        }
+        out
+    }
+
+    /// Find the child named `leaf` within `loc`, matched case-insensitively
+    /// against its long name, or its short name when it has no long name; the
+    /// short alias of an entry with a long name is not matched.
+    fn find_entry(&self, loc: DirLoc, leaf: &str) -> Option<RawEntry> {
+        self.list_raw(loc).into_iter()
+            .find(|e| e.name.eq_ignore_ascii_case(leaf))
+    }
+
+    /// Resolve `path` to the directory it names, descending from the root.
+    fn resolve_dir(&self, path: &Path) -> io::Result<DirLoc> {
+        let mut loc = if self.lay.width == FatWidth::Fat32 {
+            DirLoc::Chain(self.root_cluster)
+        } else {
+            DirLoc::FixedRoot
+        };
+        for comp in path.iter() {
+            let comp = comp.to_string_lossy();
+            if comp == "/" || comp.is_empty() {
+                continue;
+            }
+            match self.find_entry(loc, &comp) {
+                Some(ref e) if e.is_dir => loc = DirLoc::Chain(e.cluster),
+                Some(_) => return Err(io::Error::new(io::ErrorKind::Other,
+                                                     format!("not a directory: {}", comp))),
+                None => return Err(io::Error::new(io::ErrorKind::NotFound,
+                                                  format!("no such directory: {}", comp))),
+            }
+        }
+        Ok(loc)
+    }
+
+    /// Read the cluster chain starting at `first`, returning at most `size`
+    /// bytes.
+    fn read_chain(&self, first: u32, size: u32) -> Vec<u8> {
+        let mut out = Vec::with_capacity(size as usize);
+        let mut cluster = first;
+        while cluster >= 2 && cluster < self.lay.width.eoc() && out.len() < size as usize {
+            let base = self.cluster_sector(cluster);
+            for s in 0..self.lay.sectors_per_cluster {
+                out.extend_from_slice(self.part.read_sector(base + s));
+            }
+            cluster = self.get_fat(cluster);
+        }
+        out.truncate(size as usize);
+        out
+    }
+}
+
+/// A directory entry as read back off the volume, before it is lowered to the
+/// filesystem-agnostic `DirEntry`.
+struct RawEntry {
+    name: String,
+    is_dir: bool,
+    size: u32,
+    cluster: u32,
+    created: DosDateTime,
+    modified: DosDateTime,
+}
+
+/// Decode the up-to-13 UTF-16 code units of a single LFN entry into a string
+/// fragment, stopping at the NUL terminator or 0xFFFF padding.
+fn lfn_fragment(e: &[u8]) -> String {
+    const SLOTS: [usize; 13] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+    let mut units = Vec::with_capacity(13);
+    for &pos in SLOTS.iter() {
+        let unit = LittleEndian::read_u16(&e[pos..pos + 2]);
+        if unit == 0x0000 || unit == 0xFFFF {
+            break;
+        }
+        units.push(unit);
+    }
+    String::from_utf16_lossy(&units)
+}
+
+/// Rebuild the `NAME.EXT` form from the 11 packed short-name bytes.
+fn short_name_to_string(raw: &[u8]) -> String {
+    let stem = String::from_utf8_lossy(&raw[0..8]);
+    let ext = String::from_utf8_lossy(&raw[8..11]);
+    let stem = stem.trim_right();
+    let ext = ext.trim_right();
+    if ext.is_empty() {
+        stem.to_string()
+    } else {
+        format!("{}.{}", stem, ext)
+    }
+}
+
+impl<'a> FileSystem for FatFs<'a> {
+    fn make_dir(&mut self, path: PathBuf, times: &Timestamps) -> io::Result<()> {
+        let parent = try!(self.parent_dir(&path));
+        let leaf = path.file_name()
+                       .map(|s| s.to_string_lossy().into_owned())
+                       .unwrap_or_default();
+        let name = self.unique_short_name(parent, &leaf);
+
+        let cluster = try!(self.alloc_cluster());
+        let short = FatFs::dir_entry(&name, 0x10, cluster, 0, times);
+        let run = lfn_run(&leaf, &name, short);
+        try!(self.push_entries(parent, &run));
+
+        // "." and ".." entries pointing at the new dir and its parent.
+        let no_times = Timestamps::none();
+        let dot = FatFs::dir_entry(b".          ", 0x10, cluster, 0, &no_times);
+        let parent_cluster = match parent {
+            DirLoc::Chain(c) => c,
+            DirLoc::FixedRoot => 0,
+        };
+        let dotdot = FatFs::dir_entry(b"..         ", 0x10, parent_cluster, 0, &no_times);
+        try!(self.push_entries(DirLoc::Chain(cluster), &[dot]));
+        try!(self.push_entries(DirLoc::Chain(cluster), &[dotdot]));
+        Ok(())
+    }
+
+    fn write_file(&mut self, path: PathBuf, data: &[u8], times: &Timestamps) {
+        let parent = self.parent_dir(&path).expect("parent directory must exist");
+        let leaf = path.file_name()
+                       .map(|s| s.to_string_lossy().into_owned())
+                       .unwrap_or_default();
+        let name = self.unique_short_name(parent, &leaf);
+
+        let csize = self.cluster_size();
+        let mut first_cluster = 0u32;
+        let mut prev = 0u32;
+        let mut offset = 0;
+        while offset < data.len() {
+            let cluster = self.alloc_cluster().expect("out of clusters writing file");
+            if first_cluster == 0 {
+                first_cluster = cluster;
+            } else {
+                self.set_fat(prev, cluster);
+            }
+            let base = self.cluster_sector(cluster);
+            let bps = self.lay.bytes_per_sector;
+            for s in 0..self.lay.sectors_per_cluster {
+                let start = offset + s * bps;
+                if start >= data.len() {
+                    break;
+                }
+                let end = ::std::cmp::min(start + bps, data.len());
+                self.part.write_sector(base + s, &data[start..end]);
+            }
+            prev = cluster;
+            offset += csize;
+        }
+
+        let short = FatFs::dir_entry(&name, 0x20, first_cluster, data.len() as u32, times);
+        let run = lfn_run(&leaf, &name, short);
+        self.push_entries(parent, &run).expect("directory full writing file");
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<DirEntry>> {
+        let loc = try!(self.resolve_dir(path));
+        Ok(self.list_raw(loc).into_iter().map(|e| DirEntry {
+            name: e.name,
+            kind: if e.is_dir { EntryKind::Dir } else { EntryKind::File },
+            size: e.size as u64,
+            times: Timestamps {
+                created: Some(e.created.to_system_time()),
+                modified: Some(e.modified.to_system_time()),
+            },
+        }).collect())
+    }
+
+    fn read_file(&self, path: &Path) -> io::Result<Vec<u8>> {
+        let parent = match path.parent() {
+            Some(p) => try!(self.resolve_dir(p)),
+            None => return Err(io::Error::new(io::ErrorKind::InvalidInput, "empty path")),
+        };
+        let leaf = match path.file_name() {
+            Some(n) => n.to_string_lossy().into_owned(),
+            None => return Err(io::Error::new(io::ErrorKind::InvalidInput, "path has no file name")),
+        };
+        match self.find_entry(parent, &leaf) {
+            Some(ref e) if e.is_dir =>
+                Err(io::Error::new(io::ErrorKind::Other, format!("is a directory: {}", leaf))),
+            Some(e) => Ok(self.read_chain(e.cluster, e.size)),
+            None => Err(io::Error::new(io::ErrorKind::NotFound, format!("no such file: {}", leaf))),
+        }
+    }
+}
+
+/// Derive an 8.3 short name (11 bytes, space padded, upper cased) from a host
+/// filename. Collisions are resolved by `short_name_numbered`, and names that
+/// do not survive the conversion get long-name entries from `lfn_run`.
+fn short_name(name: &str) -> [u8; 11] {
+    let mut out = [b' '; 11];
+    let (stem, ext) = match name.rfind('.') {
+        Some(i) if i != 0 => (&name[..i], &name[i + 1..]),
+        _ => (name, ""),
+    };
+    for (i, c) in stem.chars().filter(|c| *c != ' ').take(8).enumerate() {
+        out[i] = sfn_char(c);
+    }
+    for (i, c) in ext.chars().filter(|c| *c != ' ').take(3).enumerate() {
+        out[8 + i] = sfn_char(c);
+    }
+    out
+}
+
+/// Map a character into the restricted short-name character set.
+fn sfn_char(c: char) -> u8 {
+    let u = c.to_uppercase().next().unwrap_or('_');
+    match u {
+        'A'...'Z' | '0'...'9' | '$' | '%' | '\'' | '-' | '_' | '@' | '~' |
+        '`' | '!' | '(' | ')' | '{' | '}' | '^' | '#' | '&' => u as u8,
+        _ => b'_',
+    }
+}
+
+/// An 8.3 short name with a `~N` numeric tail, truncating the stem so the
+/// result still fits in eight characters (`LONGNA~1`).
+fn short_name_numbered(name: &str, n: u32) -> [u8; 11] {
+    let mut out = short_name(name);
+    let tail = format!("~{}", n);
+    // The stem occupies the first eight bytes; drop enough trailing characters
+    // to make room for the tail, then splice it in before the first pad space.
+    let stem_len = out[..8].iter().position(|&b| b == b' ').unwrap_or(8);
+    let keep = ::std::cmp::min(stem_len, 8 - tail.len());
+    for (i, b) in tail.bytes().enumerate() {
+        out[keep + i] = b;
+    }
+    out
+}
+
+/// The short-name checksum each LFN entry carries, per the VFAT spec.
+fn lfn_checksum(name: &[u8; 11]) -> u8 {
+    let mut sum: u8 = 0;
+    for &byte in name.iter() {
+        sum = ((sum & 1) << 7).wrapping_add(sum >> 1).wrapping_add(byte);
+    }
+    sum
+}
+
+/// True when `leaf` does not round-trip losslessly through `short`, so it needs
+/// a long name to be preserved (mixed case, length, or disallowed characters).
+fn needs_lfn(leaf: &str, short: &[u8; 11]) -> bool {
+    let stem = String::from_utf8_lossy(&short[..8]);
+    let ext = String::from_utf8_lossy(&short[8..11]);
+    let stem = stem.trim_right();
+    let ext = ext.trim_right();
+    let rebuilt = if ext.is_empty() {
+        stem.to_string()
+    } else {
+        format!("{}.{}", stem, ext)
+    };
+    leaf != rebuilt
+}
+
+/// Assemble the directory run for `leaf`: any LFN entries (in reverse order,
+/// the last piece flagged 0x40) followed by the short entry.
+fn lfn_run(leaf: &str, sfn: &[u8; 11], short_entry: [u8; 32]) -> Vec<[u8; 32]> {
+    if !needs_lfn(leaf, sfn) {
+        return vec![short_entry];
+    }
+
+    let units: Vec<u16> = leaf.encode_utf16().collect();
+    let checksum = lfn_checksum(sfn);
+    let count = (units.len() + 12) / 13; // 13 UTF-16 units per LFN entry
+
+    let mut run = Vec::with_capacity(count + 1);
+    for seq in (1..count + 1).rev() {
+        let mut e = [0u8; 32];
+        e[0] = seq as u8 | if seq == count { 0x40 } else { 0 };
+        e[11] = 0x0F; // long-name attribute
+        e[13] = checksum;
+        // FstClusLO is always zero for LFN entries (bytes 26..28 stay 0).
+
+        // The 13 code units are split 5 + 6 + 2 across three byte regions.
+        let slots: [usize; 13] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+        let base = (seq - 1) * 13;
+        for (k, &pos) in slots.iter().enumerate() {
+            let unit = if base + k < units.len() {
+                units[base + k]
+            } else if base + k == units.len() {
+                0x0000 // NUL terminator
+            } else {
+                0xFFFF // padding
+            };
+            LittleEndian::write_u16(&mut e[pos..pos + 2], unit);
+        }
+        run.push(e);
+    }
+    run.push(short_entry);
+    run
+}
+
+#[cfg(test)]
+mod test {
+    use super::{format, FatFs};
+    use disk::{self, Format, PartitionInfo, RamDisk};
+    use fs::{EntryKind, FileSystem, Timestamps};
+    use std::path::{Path, PathBuf};
+
+    /// A freshly formatted 1MiB image with an MBR partition at sector 64.
+    fn formatted() -> RamDisk {
+        let mut disk = RamDisk::new(2048);
+        let pinfo = PartitionInfo {
+            format: Format::FatAuto,
+            size: 2048 - 64,
+            start: 64,
+            bootable: true,
+        };
+        disk::set_pinfo(&mut disk, 0, &pinfo).unwrap();
+        {
+            let mut part = disk::get_partition(&mut disk, 0).unwrap();
+            format(&mut part, Format::FatAuto).unwrap();
+        }
+        disk
+    }
+
+    #[test]
+    fn round_trips_long_names_through_read_path() {
+        let mut disk = formatted();
+        let times = Timestamps::none();
+
+        // Write a mixed-case directory and a long file name so the read path
+        // has to reassemble LFN chains, not just short 8.3 entries.
+        {
+            let part = disk::get_partition(&mut disk, 0).unwrap();
+            let mut fs = FatFs::open(part).unwrap();
+            fs.make_dir(PathBuf::from("MixedCaseDir"), &times).unwrap();
+            fs.write_file(PathBuf::from("MixedCaseDir/LongFileName.txt"),
+                          b"hello world", &times);
+        }
+
+        let part = disk::get_partition(&mut disk, 0).unwrap();
+        let fs = FatFs::open(part).unwrap();
+
+        let root = fs.read_dir(Path::new("/")).unwrap();
+        assert!(root.iter().any(|e| e.name == "MixedCaseDir" && e.kind == EntryKind::Dir));
+
+        let listing = fs.read_dir(Path::new("MixedCaseDir")).unwrap();
+        assert!(listing.iter().any(|e| e.name == "LongFileName.txt"
+                                       && e.kind == EntryKind::File
+                                       && e.size == 11));
+
+        let data = fs.read_file(Path::new("MixedCaseDir/LongFileName.txt")).unwrap();
+        assert_eq!(data, b"hello world".to_vec());
+    }
+}
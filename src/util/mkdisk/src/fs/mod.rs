@@ -0,0 +1,73 @@
+//! Filesystems that can be laid down inside a partition.
+//!
+//! Only FAT is implemented so far. The `FileSystem` trait is what the image
+//! builder drives while copying the host tree in; `disk::mount` hands back a
+//! boxed implementation for the partition it is asked about.
+
+pub mod fat;
+
+use std::fs::Metadata;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// Creation and modification times carried over from the host entry, to be
+/// stamped into the filesystem's own (coarser) timestamp fields.
+#[derive(Copy, Clone)]
+pub struct Timestamps {
+    pub created: Option<SystemTime>,
+    pub modified: Option<SystemTime>,
+}
+
+impl Timestamps {
+    /// Pull what we can from the host metadata; either field may be missing on
+    /// platforms that do not record it.
+    pub fn from_metadata(meta: &Metadata) -> Timestamps {
+        Timestamps {
+            created: meta.created().ok(),
+            modified: meta.modified().ok(),
+        }
+    }
+
+    /// No timestamps available; the filesystem falls back to its epoch.
+    pub fn none() -> Timestamps {
+        Timestamps { created: None, modified: None }
+    }
+}
+
+/// Whether a directory entry names a regular file or a subdirectory.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum EntryKind {
+    File,
+    Dir,
+}
+
+/// One entry returned from the read side, filesystem-agnostic.
+pub struct DirEntry {
+    pub name: String,
+    pub kind: EntryKind,
+    /// Size in bytes; zero for directories.
+    pub size: u64,
+    pub times: Timestamps,
+}
+
+/// A filesystem living inside a partition.
+///
+/// The `make_dir`/`write_file` half is driven while building an image; the
+/// `read_dir`/`read_file` half lets the `ls` and `cat` subcommands inspect one
+/// after the fact, without mounting it through the host kernel.
+pub trait FileSystem {
+    /// Create a directory (and it alone; parents are created first by the
+    /// caller walking the tree top-down).
+    fn make_dir(&mut self, path: PathBuf, times: &Timestamps) -> io::Result<()>;
+
+    /// Write a regular file with the given contents.
+    fn write_file(&mut self, path: PathBuf, data: &[u8], times: &Timestamps);
+
+    /// List the entries of the directory at `path` (the volume root for an
+    /// empty path).
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<DirEntry>>;
+
+    /// Read the whole contents of the regular file at `path`.
+    fn read_file(&self, path: &Path) -> io::Result<Vec<u8>>;
+}
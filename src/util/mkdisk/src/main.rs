@@ -1,7 +1,7 @@
 #![feature(slice_bytes, path_relative_from)]
 
 use std::fs::File;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::ops::DerefMut;
 
 extern crate byteorder;
@@ -13,15 +13,23 @@ use docopt::Docopt;
 
 mod disk;
 mod fs;
+mod sparse;
+mod split;
 
 use fs::*;
 use disk::*;
+use sparse::OutputFormat;
+use split::SplitWriter;
 
 static VERSION: &'static str = "0.0.1";
 static USAGE: &'static str = "
-Usage: mkdisk [options] <dir>
+Usage:
+    mkdisk ls [options] <image> <path>
+    mkdisk cat [options] <image> <path>
+    mkdisk join [options] <base> <out>
+    mkdisk [options] <dir>
 
-Creates a bootable disk image.
+Creates a bootable disk image, or inspects an existing one.
 
 Options:
     -h, --help     Print this help message
@@ -29,6 +37,10 @@ Options:
     -s, --size=SIZE         The fixed size of the disk image [default: 4MiB]
     -o, --out=FILE          The output disk image file
     -b, --bootloader=FILE   The bootloader to use for the first few sectors
+    -t, --table=KIND        The partition table to write: gpt or mbr [default: mbr]
+    -f, --format=KIND       The output image format: raw or sparse [default: raw]
+    --split=SIZE            Split the output across parts of at most SIZE bytes
+    --sector-size=BYTES     Logical sector size: 512 or 4096 [default: 512]
 
 File sizes measured using KB = 1000, KiB=1024 etc
 ";
@@ -43,12 +55,96 @@ fn main() {
                       .unwrap_or_else(|e| e.exit());
 
 
-    let mut config = Config::new(args);
-    config.exec();
+    if args.get_bool("ls") {
+        run_ls(&args);
+    } else if args.get_bool("cat") {
+        run_cat(&args);
+    } else if args.get_bool("join") {
+        run_join(&args);
+    } else {
+        let mut config = Config::new(args);
+        config.exec();
+    }
+}
+
+/// Interpret the `--sector-size` option, panicking on an unsupported value.
+fn parse_sector_size(args: &docopt::ArgvMap) -> usize {
+    match args.get_str("--sector-size") {
+        "512"  => 512,
+        "4096" => 4096,
+        other  => panic!("Unsupported sector size `{}`: use 512 or 4096", other),
+    }
+}
+
+/// Load an existing image file into a `RamDisk`, honouring `--sector-size`.
+/// A sparse (`SPRS`) container is decoded back to its full sector stream.
+fn load_image(args: &docopt::ArgvMap) -> RamDisk {
+    use std::io::{Cursor, Read};
+
+    let ssize = parse_sector_size(args);
+    let path = args.get_str("<image>");
+    let mut file = File::open(path)
+                        .unwrap_or_else(|e| panic!("Unable to open image `{}`: {}", path, e));
+    let mut bytes = Vec::new();
+    file.read_to_end(&mut bytes)
+        .unwrap_or_else(|e| panic!("Unable to read image `{}`: {}", path, e));
+
+    // A sparse image carries the `SPRS` magic and its own sector size; expand
+    // it rather than parsing the container as raw sectors.
+    if bytes.len() >= 4 && &bytes[..4] == &sparse::MAGIC[..] {
+        let image = sparse::read(&mut Cursor::new(bytes))
+                        .unwrap_or_else(|e| panic!("Unable to decode sparse image `{}`: {}", path, e));
+        let mut flat = Vec::with_capacity(image.sectors.len() * image.sector_size);
+        for sector in &image.sectors {
+            flat.extend_from_slice(sector);
+        }
+        return RamDisk::from_raw(&flat, image.sector_size);
+    }
+
+    RamDisk::from_raw(&bytes, ssize)
+}
+
+/// `mkdisk ls <image> <path>`: list the directory at `path`.
+fn run_ls(args: &docopt::ArgvMap) {
+    let mut disk = load_image(args);
+    let fs = disk::mount(&mut disk, 0).unwrap();
+    let entries = fs.read_dir(Path::new(args.get_str("<path>")))
+                    .unwrap_or_else(|e| panic!("Unable to list `{}`: {}", args.get_str("<path>"), e));
+    for entry in entries {
+        let kind = if entry.kind == EntryKind::Dir { 'd' } else { '-' };
+        println!("{} {:>10} {}", kind, entry.size, entry.name);
+    }
+}
+
+/// `mkdisk cat <image> <path>`: write the contents of `path` to stdout.
+fn run_cat(args: &docopt::ArgvMap) {
+    use std::io::Write;
+
+    let mut disk = load_image(args);
+    let fs = disk::mount(&mut disk, 0).unwrap();
+    let data = fs.read_file(Path::new(args.get_str("<path>")))
+                 .unwrap_or_else(|e| panic!("Unable to read `{}`: {}", args.get_str("<path>"), e));
+    ::std::io::stdout().write_all(&data).unwrap();
+}
+
+/// `mkdisk join <base> <out>`: reassemble the `base.000`, `base.001`, … parts
+/// written by `--split` back into a single image.
+fn run_join(args: &docopt::ArgvMap) {
+    let base = PathBuf::from(args.get_str("<base>"));
+    let out_path = args.get_str("<out>");
+    let mut out = File::create(out_path)
+                       .unwrap_or_else(|e| panic!("Unable to create `{}`: {}", out_path, e));
+    let total = split::join(&base, &mut out)
+                    .unwrap_or_else(|e| panic!("Unable to join parts for `{:?}`: {}", base, e));
+    info!("joined {} bytes into {}", total, out_path);
 }
 
 struct Config {
     dsize: usize,
+    ssize: usize,
+    table: PartitionTable,
+    format: OutputFormat,
+    split: Option<u64>,
     src: PathBuf,
 
     boot_path: PathBuf,
@@ -63,6 +159,19 @@ impl Config {
         // default is 4MiB, as specified in USAGE
         let dsize = parse_size(args.get_str("-s"));
 
+        let ssize = parse_sector_size(&args);
+
+        let table = PartitionTable::parse(args.get_str("-t"))
+                        .unwrap_or_else(|e| panic!("{}", e));
+
+        let format = OutputFormat::parse(args.get_str("-f"))
+                         .unwrap_or_else(|e| panic!("{}", e));
+
+        let split = match args.get_str("--split") {
+            "" => None,
+            s  => Some(parse_size(s) as u64),
+        };
+
         let boot_path: PathBuf = match args.get_str("-b") {
             ""   => panic!("Bootloader unspecified: use `-b` or `--bootloader`"),
             path => {
@@ -89,6 +198,10 @@ impl Config {
 
         Config {
             dsize: dsize,
+            ssize: ssize,
+            table: table,
+            format: format,
+            split: split,
             src: src,
 
             boot_path: boot_path,
@@ -109,40 +222,47 @@ impl Config {
             panic!("Source path is not a folder: `{}`", &self.src.display());
         }
 
-        let sectors = self.dsize / 512;
+        let ssize = self.ssize;
+        let sectors = self.dsize / ssize;
 
         // ensure room for filesystem
-        assert!(sectors >= 128, "Minimum disk size is 64KiB");
+        assert!(sectors * ssize >= 64 * 1024, "Minimum disk size is 64KiB");
 
-        let mut disk = RamDisk::new(sectors);
+        let mut disk = RamDisk::with_sector_size(sectors, ssize);
 
         let mut i = 0;
         loop {
-            let mut sector: [u8; 512] = [0; 512];
+            let mut sector = vec![0u8; ssize];
             match self.boot.read(&mut sector) {
-                // Ok(n) if n == 512 => { }, // read sector fine
-                // Ok(n) if n < 512  => { break; } // finished reading bootloader
                 Ok(0) => { break; }
                 Ok(n) => { }
                 Err(e) => { panic!("Unable to read bootloader `{}`: {}", self.out_path.display(), e); },
-                // _ => unreachable!(),
             }
             disk.write_sector(i, &sector);
             i += 1;
         }
 
 
+        // Partitions are aligned to sector 64: historically a cylinder
+        // boundary for the MBR, and comfortably past the GPT array for GPT.
+        let start = 64;
+        // Pick the FAT width from the partition geometry; FAT32 is invalid for
+        // the small images this tool is happy to produce.
+        let fat_format = fs::fat::resolve_format(sectors - start, ssize, Format::FatAuto);
         let pinfo = PartitionInfo {
-            format: Format::Fat32,
-            size: sectors - 64,
-            start: 64, // historically, partitions are aligned to cylinder boundaries, so start on sector 64
+            format: fat_format,
+            size: sectors - start,
+            start: start,
             bootable: true,
         };
-        disk::set_pinfo(&mut disk, 0, &pinfo).unwrap();
+        match self.table {
+            PartitionTable::Mbr => disk::set_pinfo(&mut disk, 0, &pinfo).unwrap(),
+            PartitionTable::Gpt => disk::write_gpt(&mut disk, &[pinfo]).unwrap(),
+        }
 
         {
             let mut partition = disk::get_partition(&mut disk, 0).unwrap();
-            fs::fat::format(&mut partition).unwrap();
+            fs::fat::format(&mut partition, fat_format).unwrap();
         }
 
 
@@ -155,8 +275,10 @@ impl Config {
 
                 debug!("Config::exec::recurse() vpath: {:?}", &vpath);
                 let ft = item.file_type().unwrap();
+                let times = item.metadata().map(|m| fs::Timestamps::from_metadata(&m))
+                                .unwrap_or_else(|_| fs::Timestamps::none());
                 if ft.is_dir() {
-                    fs.make_dir(vpath).unwrap();
+                    fs.make_dir(vpath, &times).unwrap();
                     recurse(fs, src, rpath);
                 } else if ft.is_file() {
                     use std::io::Read;
@@ -169,7 +291,7 @@ impl Config {
                     let mut v = Vec::new();
                     file.read_to_end(&mut v).unwrap();
 
-                    fs.write_file(vpath, &v);
+                    fs.write_file(vpath, &v, &times);
                 }
             }
         }
@@ -177,11 +299,34 @@ impl Config {
         let mut fs = disk::mount(&mut disk, 0).unwrap();
         recurse(fs.deref_mut(), &self.src, self.src.clone());
 
-        for sector in &*disk {
-            use std::io::Write;
-            self.out.write(sector);
+        match self.split {
+            // Write the stream across numbered parts rather than one file.
+            Some(cap) => {
+                let mut parts = SplitWriter::new(&self.out_path, cap);
+                write_image(&mut parts, &disk, self.format).unwrap();
+            }
+            None => {
+                write_image(&mut self.out, &disk, self.format).unwrap();
+            }
+        }
+    }
+}
+
+/// Emit the whole `disk` to `out` in the chosen format. Keeping this off
+/// `Config` lets the split and single-file paths share one sink-agnostic body.
+fn write_image<W: ::std::io::Write>(out: &mut W, disk: &RamDisk, format: OutputFormat)
+    -> ::std::io::Result<()> {
+    match format {
+        OutputFormat::Raw => {
+            for sector in disk.iter() {
+                try!(out.write_all(sector));
+            }
+        }
+        OutputFormat::Sparse => {
+            try!(sparse::write(out, disk));
         }
     }
+    Ok(())
 }
 
 // TODO: error handling
@@ -0,0 +1,95 @@
+//! Writing an image across several sequentially-numbered parts.
+//!
+//! Large images are awkward to move around, not least on FAT media. A
+//! `SplitWriter` is an `io::Write` sink that caps each part at a fixed size
+//! and rolls over to the next file (`out.disk.000`, `out.disk.001`, …) at the
+//! boundary. A single `write` is never torn across two parts, so callers that
+//! hand us whole sectors keep their sectors intact.
+
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+/// An `io::Write` that spills across numbered parts of at most `cap` bytes; a
+/// single write larger than `cap` goes whole into a part of its own.
+pub struct SplitWriter {
+    base: PathBuf,
+    cap: u64,
+    index: usize,
+    written: u64,
+    current: Option<File>,
+}
+
+impl SplitWriter {
+    /// Start writing parts named after `base` (`base.000`, `base.001`, …),
+    /// each no larger than `cap` bytes unless one write alone exceeds `cap`.
+    pub fn new<P: AsRef<Path>>(base: P, cap: u64) -> SplitWriter {
+        SplitWriter {
+            base: base.as_ref().to_path_buf(),
+            cap: cap,
+            index: 0,
+            written: 0,
+            current: None,
+        }
+    }
+
+    /// Path of part `index` for a given base: `base` with a `.NNN` suffix.
+    pub fn part_path(base: &Path, index: usize) -> PathBuf {
+        let mut name = base.as_os_str().to_os_string();
+        name.push(format!(".{:03}", index));
+        PathBuf::from(name)
+    }
+
+    /// Open the next part, advancing the index.
+    fn open_next(&mut self) -> io::Result<()> {
+        let path = SplitWriter::part_path(&self.base, self.index);
+        self.current = Some(try!(File::create(&path)));
+        self.index += 1;
+        self.written = 0;
+        Ok(())
+    }
+}
+
+impl Write for SplitWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.current.is_none() {
+            try!(self.open_next());
+        }
+        // Roll over before a write that would overflow the current part, but
+        // only if the part already holds something — an oversized lone write
+        // still has to go somewhere.
+        if self.written > 0 && self.written + buf.len() as u64 > self.cap {
+            try!(self.open_next());
+        }
+        let n = try!(self.current.as_mut().unwrap().write(buf));
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.current {
+            Some(ref mut f) => f.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Reassemble the parts written for `base` into `out`, in order, stopping at
+/// the first missing part. Returns the total number of bytes copied.
+pub fn join<W: Write>(base: &Path, out: &mut W) -> io::Result<u64> {
+    let mut total = 0u64;
+    let mut index = 0;
+    loop {
+        let path = SplitWriter::part_path(base, index);
+        let mut file = match File::open(&path) {
+            Ok(f) => f,
+            Err(_) => break,
+        };
+        let mut buf = Vec::new();
+        try!(file.read_to_end(&mut buf));
+        try!(out.write_all(&buf));
+        total += buf.len() as u64;
+        index += 1;
+    }
+    Ok(total)
+}